@@ -0,0 +1,355 @@
+#![no_std]
+
+use cortex_m::peripheral::SCB;
+use usb_device::class_prelude::*;
+
+// Boot requests understood by dfu-boot, kept in BKP_DR1 across a system reset
+pub const BOOT_REQUEST_BOOTLOADER: u16 = 0x4446; // "DF"
+pub const BOOT_REQUEST_APPLICATION: u16 = 0x4150; // "AP"
+
+const RCC_APB1ENR: u32 = 0x4002_101c;
+const PWR_CR: u32 = 0x4000_7000;
+const BKP_DR1: u32 = 0x4000_6c04;
+
+const RCC_APB1ENR_BKPEN: u32 = 1 << 27;
+const RCC_APB1ENR_PWREN: u32 = 1 << 28;
+const PWR_CR_DBP: u32 = 1 << 8;
+
+const CLASS_APPLICATION_SPECIFIC: u8 = 0xfe;
+const SUBCLASS_DFU: u8 = 0x01;
+const PROTOCOL_RUNTIME: u8 = 0x01;
+const DESC_DFU_FUNCTIONAL: u8 = 0x21;
+
+const DFU_DETACH: u8 = 0;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_GETSTATE: u8 = 5;
+
+const STATE_APP_IDLE: u8 = 0;
+const STATE_APP_DETACH: u8 = 1;
+
+pub fn set_boot_request(request: u16) {
+    unsafe {
+        let apb1enr = RCC_APB1ENR as *mut u32;
+        apb1enr.write_volatile(apb1enr.read_volatile() | RCC_APB1ENR_BKPEN | RCC_APB1ENR_PWREN);
+        let cr = PWR_CR as *mut u32;
+        cr.write_volatile(cr.read_volatile() | PWR_CR_DBP);
+        (BKP_DR1 as *mut u32).write_volatile(request as u32);
+    }
+}
+
+// Resets the device into the bootloader's DFU mode
+pub fn reboot_into_bootloader() -> ! {
+    set_boot_request(BOOT_REQUEST_BOOTLOADER);
+    SCB::sys_reset();
+}
+
+pub struct DfuRuntime<B: UsbBus> {
+    comm_if: InterfaceNumber,
+    detach_timeout_ms: u16,
+    detach: bool,
+    detach_armed: bool,
+    // reboot_into_bootloader(), tests catch it
+    reboot: fn() -> !,
+    _bus: core::marker::PhantomData<B>,
+}
+
+impl<B: UsbBus> DfuRuntime<B> {
+    // detach_timeout_ms is reported to the host as wDetachTimeout. The device
+    // reports bitWillDetach and reboots into the bootloader right after
+    // DFU_DETACH, so the timeout the host sends along (wValue) is not waited for.
+    pub fn new(alloc: &UsbBusAllocator<B>, detach_timeout_ms: u16) -> DfuRuntime<B> {
+        DfuRuntime {
+            comm_if: alloc.interface(),
+            detach_timeout_ms: detach_timeout_ms,
+            detach: false,
+            detach_armed: false,
+            reboot: reboot_into_bootloader,
+            _bus: core::marker::PhantomData,
+        }
+    }
+
+    pub fn detach_requested(&self) -> bool {
+        self.detach
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for DfuRuntime<B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        writer.interface(self.comm_if,
+                         CLASS_APPLICATION_SPECIFIC,
+                         SUBCLASS_DFU,
+                         PROTOCOL_RUNTIME)?;
+
+        writer.write(DESC_DFU_FUNCTIONAL, &[
+                     0x08 | 0x02 | 0x01, // bmAttributes: will detach, upload, download
+                     (self.detach_timeout_ms & 0xff) as u8,
+                     ((self.detach_timeout_ms >> 8) & 0xff) as u8, // wDetachTimeout
+                     0x00, 0x01, // wTransferSize
+                     0x1a, 0x01, // bcdDFUVersion, DfuSe
+                     ])
+    }
+
+    fn reset(&mut self) {
+        // the host may reset the bus instead of waiting for the device to detach
+        if self.detach {
+            (self.reboot)();
+        }
+    }
+
+    fn poll(&mut self) {
+        // DFU_DETACH is accepted during a poll, its status stage has completed
+        // by the following one
+        if self.detach {
+            if self.detach_armed {
+                (self.reboot)();
+            }
+            self.detach_armed = true;
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !(req.request_type == control::RequestType::Class
+             && req.recipient == control::Recipient::Interface
+             && req.index == u8::from(self.comm_if) as u16) {
+            return;
+        }
+
+        let state = if self.detach { STATE_APP_DETACH } else { STATE_APP_IDLE };
+        match req.request {
+            DFU_GETSTATUS => {
+                xfer.accept_with(&[0, 0, 0, 0, state, 0]).ok();
+            },
+            DFU_GETSTATE => {
+                xfer.accept_with(&[state]).ok();
+            },
+            _ => {
+                xfer.reject().ok();
+            },
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !(req.request_type == control::RequestType::Class
+             && req.recipient == control::Recipient::Interface
+             && req.index == u8::from(self.comm_if) as u16) {
+            return;
+        }
+
+        match req.request {
+            // wValue, the longest the host waits for the reset, does not
+            // matter with bitWillDetach
+            DFU_DETACH => {
+                self.detach = true;
+                xfer.accept().ok();
+            },
+            _ => {
+                xfer.reject().ok();
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{Arc, Mutex};
+    use std::vec;
+    use std::vec::Vec;
+    use usb_device::bus::PollResult;
+    use usb_device::UsbDirection;
+    use usb_device::prelude::*;
+
+    // cortex-m calls these asm shims, which are only linked in on the target
+    #[no_mangle]
+    extern "C" fn __nop() {}
+
+    #[no_mangle]
+    extern "C" fn __dsb() {}
+
+    // endpoint 0 as the device sees it, the test plays the host
+    #[derive(Default)]
+    struct Ep0 {
+        setup: Option<[u8; 8]>,
+        // zero length OUT packet, the status stage of IN transfers
+        status_out: bool,
+        written: Vec<u8>,
+        in_complete: bool,
+        stalled: bool,
+        reset: bool,
+    }
+
+    struct MockBus {
+        ep0: Arc<Mutex<Ep0>>,
+    }
+
+    impl UsbBus for MockBus {
+        fn alloc_ep(&mut self, ep_dir: UsbDirection, ep_addr: Option<EndpointAddress>, _ep_type: EndpointType,
+                    _max_packet_size: u16, _interval: u8) -> usb_device::Result<EndpointAddress> {
+            Ok(ep_addr.unwrap_or_else(|| EndpointAddress::from_parts(0, ep_dir)))
+        }
+
+        fn enable(&mut self) {}
+
+        fn reset(&self) {}
+
+        fn set_device_address(&self, _addr: u8) {}
+
+        fn write(&self, _ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+            let mut ep0 = self.ep0.lock().unwrap();
+            ep0.written.extend_from_slice(buf);
+            ep0.in_complete = true;
+            Ok(buf.len())
+        }
+
+        fn read(&self, _ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
+            let mut ep0 = self.ep0.lock().unwrap();
+            if let Some(setup) = ep0.setup.take() {
+                buf[..8].copy_from_slice(&setup);
+                return Ok(8);
+            }
+            if ep0.status_out {
+                ep0.status_out = false;
+                return Ok(0);
+            }
+            Err(UsbError::WouldBlock)
+        }
+
+        fn set_stalled(&self, _ep_addr: EndpointAddress, stalled: bool) {
+            self.ep0.lock().unwrap().stalled = stalled;
+        }
+
+        fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+            self.ep0.lock().unwrap().stalled
+        }
+
+        fn suspend(&self) {}
+
+        fn resume(&self) {}
+
+        fn poll(&self) -> PollResult {
+            let mut ep0 = self.ep0.lock().unwrap();
+            if ep0.reset {
+                ep0.reset = false;
+                return PollResult::Reset;
+            }
+            let in_complete = core::mem::replace(&mut ep0.in_complete, false);
+            if ep0.setup.is_none() && !ep0.status_out && !in_complete {
+                return PollResult::None;
+            }
+            PollResult::Data {
+                ep_out: ep0.status_out as u16,
+                ep_in_complete: in_complete as u16,
+                ep_setup: ep0.setup.is_some() as u16,
+            }
+        }
+    }
+
+    fn reboot() -> ! {
+        panic!("reboot into the bootloader");
+    }
+
+    struct Host {
+        ep0: Arc<Mutex<Ep0>>,
+    }
+
+    impl Host {
+        // sends a SETUP packet and returns what the device answered, None if
+        // it stalled
+        fn request<C: UsbClass<MockBus>>(&self, dev: &mut UsbDevice<MockBus>, class: &mut C,
+                                         setup: [u8; 8]) -> Option<Vec<u8>> {
+            {
+                let mut ep0 = self.ep0.lock().unwrap();
+                ep0.setup = Some(setup);
+                ep0.written.clear();
+            }
+            dev.poll(&mut [class]);
+            let mut ep0 = self.ep0.lock().unwrap();
+            if ep0.stalled {
+                return None;
+            }
+            if setup[0] & 0x80 != 0 {
+                ep0.status_out = true;
+            }
+            Some(ep0.written.clone())
+        }
+
+        // completes the status stage of the last request
+        fn poll<C: UsbClass<MockBus>>(&self, dev: &mut UsbDevice<MockBus>, class: &mut C) -> bool {
+            dev.poll(&mut [class])
+        }
+    }
+
+    fn rebooted(f: impl FnOnce()) -> bool {
+        panic::catch_unwind(AssertUnwindSafe(f)).is_err()
+    }
+
+    const GET_CONFIGURATION_DESCRIPTOR: [u8; 8] = [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xff, 0x00];
+    // to interface 0, wValue 1000 ms
+    const DETACH: [u8; 8] = [0x21, DFU_DETACH, 0xe8, 0x03, 0x00, 0x00, 0x00, 0x00];
+    const GETSTATE: [u8; 8] = [0xa1, DFU_GETSTATE, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00];
+    const GETSTATUS: [u8; 8] = [0xa1, DFU_GETSTATUS, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00];
+
+    fn bus() -> (UsbBusAllocator<MockBus>, Host) {
+        let ep0 = Arc::new(Mutex::new(Ep0::default()));
+        (UsbBusAllocator::new(MockBus { ep0: ep0.clone() }), Host { ep0 })
+    }
+
+    #[test]
+    fn descriptors() {
+        let (alloc, host) = bus();
+        let mut runtime = DfuRuntime::new(&alloc, 0x1234);
+        let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x41ca, 0x2137)).max_packet_size_0(64).build();
+        let config = host.request(&mut dev, &mut runtime, GET_CONFIGURATION_DESCRIPTOR).unwrap();
+        assert_eq!(config.len(), 27);
+        assert_eq!(&config[9..], &[
+            9, 0x04, 0, 0, 0, CLASS_APPLICATION_SPECIFIC, SUBCLASS_DFU, PROTOCOL_RUNTIME, 0,
+            9, DESC_DFU_FUNCTIONAL, 0x0b, 0x34, 0x12, 0x00, 0x01, 0x1a, 0x01,
+        ]);
+    }
+
+    #[test]
+    fn detach() {
+        let (alloc, host) = bus();
+        let mut runtime = DfuRuntime::new(&alloc, 1000);
+        runtime.reboot = reboot;
+        let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x41ca, 0x2137)).max_packet_size_0(64).build();
+
+        assert_eq!(host.request(&mut dev, &mut runtime, GETSTATUS), Some(vec![0, 0, 0, 0, STATE_APP_IDLE, 0]));
+        assert!(!rebooted(|| { host.poll(&mut dev, &mut runtime); }));
+        assert_eq!(host.request(&mut dev, &mut runtime, GETSTATE), Some(vec![STATE_APP_IDLE]));
+        assert!(!rebooted(|| { host.poll(&mut dev, &mut runtime); }));
+        // requests to another interface are not for the runtime
+        let mut other = DETACH;
+        other[4] = 1;
+        assert_eq!(host.request(&mut dev, &mut runtime, other), None);
+        assert!(!runtime.detach_requested());
+
+        assert_eq!(host.request(&mut dev, &mut runtime, DETACH), Some(vec![]));
+        assert!(runtime.detach_requested());
+        // rebooted once the status stage completed, without waiting for wValue
+        assert!(rebooted(|| { host.poll(&mut dev, &mut runtime); }));
+    }
+
+    #[test]
+    fn reset_after_detach() {
+        let (alloc, host) = bus();
+        let mut runtime = DfuRuntime::new(&alloc, 1000);
+        runtime.reboot = reboot;
+        let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x41ca, 0x2137)).max_packet_size_0(64).build();
+
+        // a reset without DFU_DETACH is left to the application
+        host.ep0.lock().unwrap().reset = true;
+        assert!(!rebooted(|| { host.poll(&mut dev, &mut runtime); }));
+
+        // the host resets the bus before the status stage of DFU_DETACH completed
+        host.ep0.lock().unwrap().setup = Some(DETACH);
+        assert!(!rebooted(|| { dev.poll(&mut [&mut runtime]); }));
+        host.ep0.lock().unwrap().reset = true;
+        assert!(rebooted(|| { host.poll(&mut dev, &mut runtime); }));
+    }
+}
@@ -0,0 +1,378 @@
+#![no_std]
+
+// DFU 1.1 state machine (DFU mode states only), kept free of usb-device and
+// hardware access so the transition table can be exercised on the host.
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DfuState {
+    AppIdle,
+    AppDetach,
+
+    DfuIdle,
+    DfuDnloadSync,
+    DfuDnloadBusy,
+    DfuDnloadIdle,
+    DfuManifestSync,
+    DfuManifest,
+    DfuManifestWaitReset,
+    DfuUploadIdle,
+    DfuError,
+}
+
+// todo: make all these useful
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DfuDeviceStatus {
+    Ok, // No error condition is present.
+    ErrTarget, // File is not targeted for use by this device.
+    ErrFile, // File is for this device but fails some vendor-specific verification test.
+    ErrWrite, // Device is unable to write memory.
+    ErrErase, // Memory erase function failed.
+    ErrCheckErased, // Memory erase check failed
+    ErrProg, // Program memory function failed.
+    ErrVerify, // Programmed memory failed verification.
+    ErrAddress, // Cannot program memory due to received address that is out of range.
+    ErrNotDone, // Received DFU_DNLOAD with wLength = 0, but device does not think it has all of the data yet.
+    ErrFirmware, // Device’s firmware is corrupt.  It cannot return to run-time (non-DFU) operations.
+    ErrVendor, // iString indicates a vendor-specific error.
+    ErrUsbR, // Device detected unexpected USB reset signaling.
+    ErrPoR, // Device detected unexpected power on reset.
+    ErrUnknown, // Something went wrong, but the device does not know what it was.
+    ErrStaledPkt, // Device stalled an unexpected request.
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Event {
+    Dnload { length: u16 },
+    Upload { short: bool }, // a short frame ends the upload
+    GetStatus,
+    GetState,
+    ClrStatus,
+    Abort,
+    Stall(DfuDeviceStatus), // request refused by the class, e.g. bad address
+    FlashDone,
+    FlashFailed(DfuDeviceStatus),
+    UsbReset,
+}
+
+pub struct DfuStateMachine {
+    state: DfuState,
+    status: DfuDeviceStatus,
+    manifestation_tolerant: bool,
+    // a block or the manifestation was received but not yet processed
+    pending: bool,
+}
+
+impl DfuStateMachine {
+    pub const fn new(manifestation_tolerant: bool) -> DfuStateMachine {
+        DfuStateMachine {
+            state: DfuState::DfuIdle,
+            status: DfuDeviceStatus::Ok,
+            manifestation_tolerant,
+            pending: false,
+        }
+    }
+
+    pub fn state(&self) -> DfuState {
+        self.state
+    }
+
+    pub fn status(&self) -> DfuDeviceStatus {
+        self.status
+    }
+
+    // true while the device is expected to erase/program/manifest
+    pub fn busy(&self) -> bool {
+        matches!(self.state, DfuState::DfuDnloadBusy | DfuState::DfuManifest)
+    }
+
+    // Returns false when the request has to be stalled.
+    pub fn handle(&mut self, event: Event) -> bool {
+        use DfuState::*;
+
+        let next = match (self.state, event) {
+            (_, Event::UsbReset) => Some(self.on_reset()),
+            (_, Event::Stall(status)) => return self.fail(status),
+            (DfuDnloadBusy, Event::FlashFailed(status))
+                | (DfuManifest, Event::FlashFailed(status)) => return self.fail(status),
+
+            (DfuIdle, Event::Dnload { length: 0 }) => None,
+            (DfuDnloadIdle, Event::Dnload { length: 0 }) => {
+                self.pending = true;
+                Some(DfuManifestSync)
+            },
+            (DfuIdle, Event::Dnload { .. })
+                | (DfuDnloadIdle, Event::Dnload { .. }) => {
+                    self.pending = true;
+                    Some(DfuDnloadSync)
+            },
+            (DfuIdle, Event::Upload { short: true })
+                | (DfuUploadIdle, Event::Upload { short: true }) => Some(DfuIdle),
+            (DfuIdle, Event::Upload { short: false })
+                | (DfuUploadIdle, Event::Upload { short: false }) => Some(DfuUploadIdle),
+            (DfuIdle, Event::Abort)
+                | (DfuDnloadIdle, Event::Abort)
+                | (DfuUploadIdle, Event::Abort) => Some(DfuIdle),
+            (DfuError, Event::ClrStatus) => {
+                self.status = DfuDeviceStatus::Ok;
+                Some(DfuIdle)
+            },
+
+            (DfuDnloadSync, Event::GetStatus) => {
+                if self.pending { Some(DfuDnloadBusy) } else { Some(DfuDnloadIdle) }
+            },
+            (DfuDnloadBusy, Event::FlashDone) => {
+                self.pending = false;
+                Some(DfuDnloadSync)
+            },
+            (DfuManifestSync, Event::GetStatus) => {
+                if self.pending { Some(DfuManifest) } else { Some(DfuIdle) }
+            },
+            (DfuManifest, Event::FlashDone) => {
+                self.pending = false;
+                if self.manifestation_tolerant {
+                    Some(DfuManifestSync)
+                } else {
+                    Some(DfuManifestWaitReset)
+                }
+            },
+
+            // the device can not answer while busy or waiting for a reset
+            (DfuDnloadBusy, _) | (DfuManifest, _) | (DfuManifestWaitReset, _) => None,
+            (_, Event::GetStatus) | (_, Event::GetState) => Some(self.state),
+            _ => None,
+        };
+
+        match next {
+            Some(state) => {
+                self.state = state;
+                true
+            },
+            None => self.fail(DfuDeviceStatus::ErrStaledPkt),
+        }
+    }
+
+    fn fail(&mut self, status: DfuDeviceStatus) -> bool {
+        // keep the first error until the host clears it
+        if self.state != DfuState::DfuError {
+            self.status = status;
+        }
+        self.state = DfuState::DfuError;
+        self.pending = false;
+        false
+    }
+
+    fn on_reset(&mut self) -> DfuState {
+        self.pending = false;
+        match self.state {
+            DfuState::DfuDnloadSync
+                | DfuState::DfuDnloadBusy
+                | DfuState::DfuDnloadIdle
+                | DfuState::DfuManifestSync
+                | DfuState::DfuManifest => {
+                    self.status = DfuDeviceStatus::ErrUsbR;
+                    DfuState::DfuError
+            },
+            DfuState::DfuError => DfuState::DfuError,
+            _ => {
+                self.status = DfuDeviceStatus::Ok;
+                DfuState::DfuIdle
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use DfuDeviceStatus::*;
+    use DfuState::*;
+
+    const BLOCK: Event = Event::Dnload { length: 256 };
+    const ZLP: Event = Event::Dnload { length: 0 };
+    const UPLOAD: Event = Event::Upload { short: false };
+    const UPLOAD_SHORT: Event = Event::Upload { short: true };
+
+    fn machine(state: DfuState, status: DfuDeviceStatus, pending: bool) -> DfuStateMachine {
+        DfuStateMachine {
+            state,
+            status,
+            manifestation_tolerant: false,
+            pending,
+        }
+    }
+
+    // every state and every kind of request, each index has to be listed
+    const STATES: usize = 11;
+    const EVENTS: usize = 12;
+
+    fn state_index(state: DfuState) -> usize {
+        match state {
+            AppIdle => 0,
+            AppDetach => 1,
+            DfuIdle => 2,
+            DfuDnloadSync => 3,
+            DfuDnloadBusy => 4,
+            DfuDnloadIdle => 5,
+            DfuManifestSync => 6,
+            DfuManifest => 7,
+            DfuManifestWaitReset => 8,
+            DfuUploadIdle => 9,
+            DfuError => 10,
+        }
+    }
+
+    fn event_index(event: Event) -> usize {
+        match event {
+            Event::Dnload { length: 0 } => 1,
+            Event::Dnload { .. } => 0,
+            Event::Upload { short: false } => 2,
+            Event::Upload { short: true } => 3,
+            Event::GetStatus => 4,
+            Event::GetState => 5,
+            Event::ClrStatus => 6,
+            Event::Abort => 7,
+            Event::Stall(_) => 8,
+            Event::FlashDone => 9,
+            Event::FlashFailed(_) => 10,
+            Event::UsbReset => 11,
+        }
+    }
+
+    const ALL_STATES: [DfuState; STATES] = [
+        AppIdle, AppDetach, DfuIdle, DfuDnloadSync, DfuDnloadBusy, DfuDnloadIdle,
+        DfuManifestSync, DfuManifest, DfuManifestWaitReset, DfuUploadIdle, DfuError,
+    ];
+
+    const ALL_EVENTS: [Event; EVENTS] = [
+        BLOCK, ZLP, UPLOAD, UPLOAD_SHORT, Event::GetStatus, Event::GetState,
+        Event::ClrStatus, Event::Abort, Event::Stall(ErrAddress), Event::FlashDone,
+        Event::FlashFailed(ErrProg), Event::UsbReset,
+    ];
+
+    // (accepted, state, status) after the request
+    type Outcome = (bool, DfuState, DfuDeviceStatus);
+
+    const fn to(state: DfuState) -> Outcome {
+        (true, state, Ok)
+    }
+    const S: Outcome = (false, DfuError, ErrStaledPkt);
+    const ADDR: Outcome = (false, DfuError, ErrAddress);
+    const PROG: Outcome = (false, DfuError, ErrProg);
+    const USBR: Outcome = (true, DfuError, ErrUsbR);
+    // in DfuError the first status stays until DFU_CLRSTATUS
+    const KEPT: Outcome = (false, DfuError, ErrFile);
+    const ANSWERED: Outcome = (true, DfuError, ErrFile);
+
+    // Rows in ALL_STATES order, starting with status Ok (ErrFile in DfuError)
+    // and a block or manifestation pending in the sync and busy states.
+    // Columns: block, zero length block, upload, short upload, GETSTATUS,
+    // GETSTATE, CLRSTATUS, ABORT, stall, flash done, flash failed, USB reset.
+    const MATRIX: [[Outcome; EVENTS]; STATES] = [
+        // AppIdle
+        [S, S, S, S, to(AppIdle), to(AppIdle), S, S, ADDR, S, S, to(DfuIdle)],
+        // AppDetach
+        [S, S, S, S, to(AppDetach), to(AppDetach), S, S, ADDR, S, S, to(DfuIdle)],
+        // DfuIdle
+        [to(DfuDnloadSync), S, to(DfuUploadIdle), to(DfuIdle), to(DfuIdle), to(DfuIdle),
+         S, to(DfuIdle), ADDR, S, S, to(DfuIdle)],
+        // DfuDnloadSync
+        [S, S, S, S, to(DfuDnloadBusy), to(DfuDnloadSync),
+         S, S, ADDR, S, S, USBR],
+        // DfuDnloadBusy
+        [S, S, S, S, S, S, S, S, ADDR, to(DfuDnloadSync), PROG, USBR],
+        // DfuDnloadIdle
+        [to(DfuDnloadSync), to(DfuManifestSync), S, S, to(DfuDnloadIdle), to(DfuDnloadIdle),
+         S, to(DfuIdle), ADDR, S, S, USBR],
+        // DfuManifestSync
+        [S, S, S, S, to(DfuManifest), to(DfuManifestSync), S, S, ADDR, S, S, USBR],
+        // DfuManifest
+        [S, S, S, S, S, S, S, S, ADDR, to(DfuManifestWaitReset), PROG, USBR],
+        // DfuManifestWaitReset
+        [S, S, S, S, S, S, S, S, ADDR, S, S, to(DfuIdle)],
+        // DfuUploadIdle
+        [S, S, to(DfuUploadIdle), to(DfuIdle), to(DfuUploadIdle), to(DfuUploadIdle),
+         S, to(DfuIdle), ADDR, S, S, to(DfuIdle)],
+        // DfuError
+        [KEPT, KEPT, KEPT, KEPT, ANSWERED, ANSWERED, to(DfuIdle), KEPT, KEPT, KEPT, KEPT, ANSWERED],
+    ];
+
+    #[test]
+    fn transitions() {
+        for &state in ALL_STATES.iter() {
+            for &event in ALL_EVENTS.iter() {
+                let status = if state == DfuError { ErrFile } else { Ok };
+                let pending = matches!(state, DfuDnloadSync | DfuDnloadBusy | DfuManifestSync | DfuManifest);
+                let mut m = machine(state, status, pending);
+                let accepted = m.handle(event);
+                let expected = MATRIX[state_index(state)][event_index(event)];
+                assert_eq!((accepted, m.state(), m.status()), expected, "{:?} {:?}", state, event);
+            }
+        }
+    }
+
+    #[test]
+    fn matrix_is_complete() {
+        for (i, &state) in ALL_STATES.iter().enumerate() {
+            assert_eq!(state_index(state), i);
+        }
+        for (i, &event) in ALL_EVENTS.iter().enumerate() {
+            assert_eq!(event_index(event), i);
+        }
+    }
+
+    // the sync states without anything pending
+    #[test]
+    fn nothing_pending() {
+        let mut m = machine(DfuDnloadSync, Ok, false);
+        assert!(m.handle(Event::GetStatus));
+        assert_eq!(m.state(), DfuDnloadIdle);
+        let mut m = machine(DfuManifestSync, Ok, false);
+        assert!(m.handle(Event::GetStatus));
+        assert_eq!(m.state(), DfuIdle);
+    }
+
+    #[test]
+    fn tolerant_manifestation() {
+        let mut m = machine(DfuManifest, Ok, true);
+        m.manifestation_tolerant = true;
+        assert!(m.handle(Event::FlashDone));
+        assert_eq!(m.state(), DfuManifestSync);
+        // manifested, the next status request returns to idle
+        assert!(m.handle(Event::GetStatus));
+        assert_eq!(m.state(), DfuIdle);
+    }
+
+    #[test]
+    fn download() {
+        let mut m = DfuStateMachine::new(false);
+        for _ in 0..2 {
+            assert!(m.handle(BLOCK));
+            assert!(m.handle(Event::GetStatus));
+            assert_eq!(m.state(), DfuDnloadBusy);
+            assert!(m.busy());
+            assert!(m.handle(Event::FlashDone));
+            assert!(m.handle(Event::GetStatus));
+            assert_eq!(m.state(), DfuDnloadIdle);
+            assert!(!m.busy());
+        }
+        assert!(m.handle(ZLP));
+        assert!(m.handle(Event::GetStatus));
+        assert_eq!(m.state(), DfuManifest);
+        assert!(m.busy());
+        assert!(m.handle(Event::FlashDone));
+        assert_eq!(m.state(), DfuManifestWaitReset);
+        assert!(m.handle(Event::UsbReset));
+        assert_eq!((m.state(), m.status()), (DfuIdle, Ok));
+    }
+
+    #[test]
+    fn first_error_is_kept() {
+        let mut m = DfuStateMachine::new(false);
+        assert!(m.handle(BLOCK));
+        assert!(!m.handle(Event::Stall(ErrAddress)));
+        assert!(!m.handle(BLOCK));
+        assert_eq!((m.state(), m.status()), (DfuError, ErrAddress));
+        assert!(m.handle(Event::ClrStatus));
+        assert_eq!((m.state(), m.status()), (DfuIdle, Ok));
+    }
+}
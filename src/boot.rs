@@ -0,0 +1,47 @@
+use stm32f1xx_hal::pac::{BKP, PWR, RCC};
+// shared with applications, which request DFU mode through the same register
+use dfu_runtime::{BOOT_REQUEST_APPLICATION, BOOT_REQUEST_BOOTLOADER};
+
+#[allow(unused)]
+#[derive(Copy, Clone, PartialEq)]
+pub(crate) enum BootRequest {
+    None,
+    Bootloader,
+    Application,
+}
+
+unsafe fn enable_backup_domain() {
+    let rcc = &*RCC::ptr();
+    let pwr = &*PWR::ptr();
+
+    rcc.apb1enr.modify(|_, w| w.pwren().set_bit().bkpen().set_bit());
+    pwr.cr.modify(|_, w| w.dbp().set_bit());
+}
+
+pub(crate) fn set_boot_request(request: BootRequest) {
+    let value = match request {
+        BootRequest::None => 0,
+        BootRequest::Bootloader => BOOT_REQUEST_BOOTLOADER,
+        BootRequest::Application => BOOT_REQUEST_APPLICATION,
+    };
+    unsafe {
+        enable_backup_domain();
+        (*BKP::ptr()).dr[0].write(|w| w.bits(value as u32));
+    }
+}
+
+// reads and clears the pending boot request
+pub(crate) fn take_boot_request() -> BootRequest {
+    let value = unsafe {
+        enable_backup_domain();
+        ((*BKP::ptr()).dr[0].read().bits() & 0xffff) as u16
+    };
+    if value != 0 {
+        set_boot_request(BootRequest::None);
+    }
+    match value {
+        BOOT_REQUEST_BOOTLOADER => BootRequest::Bootloader,
+        BOOT_REQUEST_APPLICATION => BootRequest::Application,
+        _ => BootRequest::None,
+    }
+}
@@ -7,15 +7,25 @@ use stm32f1xx_hal::{
 pub(crate) const DEBUG: bool = true;
 
 // USB constants
-pub(crate) const USB_MANUFACTURER: &'static str = "aika";
-pub(crate) const USB_PRODUCT: &'static str = concat!("DFU Bootloader ", env!("CARGO_PKG_VERSION"));
-pub(crate) const USB_SERIAL_NO: &'static str = "8971842209015648";
+pub(crate) const USB_MANUFACTURER: &str = "aika";
+pub(crate) const USB_PRODUCT: &str = concat!("DFU Bootloader ", env!("CARGO_PKG_VERSION"));
+pub(crate) const USB_SERIAL_NO: &str = "8971842209015648";
 
-// Alternate Setting 0 name string
-pub(crate) const DFU_AL0: &'static str = concat!("DFU Bootloader ", env!("CARGO_PKG_VERSION"));
+// Alternate Setting names, followed by the DfuSe memory layout
+pub(crate) const DFU_AL0: &str = "@Internal Flash ";
+pub(crate) const DFU_AL1: &str = "@Bootloader Flags ";
+pub(crate) const DFU_AL2: &str = "@Option Bytes ";
+pub(crate) const DFU_AL3: &str = "@SRAM ";
+
+// DFU functional descriptor attributes. DfuSe hosts expect the device to
+// detach on its own after manifestation (dfu-util :leave)
+pub(crate) const MANIFESTATION_TOLERANT: bool = false;
+pub(crate) const WILL_DETACH: bool = true;
+
+pub(crate) const RAM_SIZE: u32 = 20 * 1024;
 
 // URL which will pop up when DFU device is plugged in
-pub(crate) const WEBUSB_URL: &'static str = "devanlai.github.io/webdfu/dfu-util";
+pub(crate) const WEBUSB_URL: &str = "devanlai.github.io/webdfu/dfu-util";
 
 pub(crate) fn usart1_config() -> Config {
     Config::default().baudrate(9_600.bps())
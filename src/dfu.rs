@@ -7,8 +7,10 @@ use core::mem;
 use crate::flash;
 use crate::flags;
 use crate::config;
+use crate::memory::{self, Memory};
 use crate::util;
 use crate::util::LOGGER;
+use dfu_state::{DfuDeviceStatus, DfuState, DfuStateMachine, Event};
 
 use stm32f1xx_hal::{
     pac::{FLASH, USART1},
@@ -20,11 +22,12 @@ use core::marker::PhantomData;
 #[allow(dead_code)]
 pub(crate) const BL_MAGIC: u32 = 0xdeadcafe;
 
-// const DFU_AL0: &'static str = "DFU Bootloader 0.2.0";
+// const DFU_AL0: &str = "DFU Bootloader 0.2.0";
 const CLASS_APPLICATION_SPECIFIC: u8 = 0xfe;
 const SUBCLASS_DFU: u8 = 0x01;
 const PROTOCOL_DFU_MODE: u8 = 0x02;
 const DESC_DFU_FUNCTIONAL: u8 = 0x21;
+const TRANSFER_SIZE: u16 = 256;
 
 #[allow(unused)]
 pub(crate) mod dfu_request {
@@ -37,43 +40,27 @@ pub(crate) mod dfu_request {
     pub const DFU_ABORT: u8 = 6; // proto 2
 }
 
+// DfuSe commands, sent as DFU_DNLOAD with wValue = 0
 #[allow(unused)]
-#[derive(Copy, Clone)]
-pub(crate) enum DfuState {
-    AppIdle,
-    AppDetach,
-
-    DfuIdle,
-    DfuDnloadSync,
-    DfuDnloadBusy,
-    DfuDnloadIdle,
-    DfuManifestSync,
-    DfuManifest,
-    DfuManifestWaitReset,
-    DfuUploadIdle,
-    DfuError,
+pub(crate) mod dfuse_command {
+    pub const GET_COMMANDS: u8 = 0x00;
+    pub const SET_ADDRESS_POINTER: u8 = 0x21;
+    pub const ERASE: u8 = 0x41;
+    pub const READ_UNPROTECT: u8 = 0x92;
 }
 
-// todo: make all these useful
-#[allow(unused)]
+const DFUSE_COMMANDS: [u8; 4] = [
+    dfuse_command::GET_COMMANDS,
+    dfuse_command::SET_ADDRESS_POINTER,
+    dfuse_command::ERASE,
+    dfuse_command::READ_UNPROTECT,
+];
+
 #[derive(Copy, Clone)]
-pub(crate) enum DfuDeviceStatus {
-    Ok, // No error condition is present.
-    ErrTarget, // File is not targeted for use by this device.
-    ErrFile, // File is for this device but fails some vendor-specific verification test.
-    ErrWrite, // Device is unable to write memory.
-    ErrErase, // Memory erase function failed.
-    ErrCheckErased, // Memory erase check failed
-    ErrProg, // Program memory function failed.
-    ErrVerify, // Programmed memory failed verification.
-    ErrAddress, // Cannot program memory due to received address that is out of range.
-    ErrNotDone, // Received DFU_DNLOAD with wLength = 0, but device does not think it has all of the data yet.
-    ErrFirmware, // Device’s firmware is corrupt.  It cannot return to run-time (non-DFU) operations.
-    ErrVendor, // iString indicates a vendor-specific error.
-    ErrUsbR, // Device detected unexpected USB reset signaling.
-    ErrPoR, // Device detected unexpected power on reset.
-    ErrUnknown, // Something went wrong, but the device does not know what it was.
-    ErrStaledPkt, // Device stalled an unexpected request.
+enum DfuseCommand {
+    Erase(u32),
+    MassErase,
+    ReadUnprotect,
 }
 
 const BIGGEST_PAGE: usize = 2048;
@@ -81,39 +68,51 @@ const BIGGEST_PAGE: usize = 2048;
 pub struct Dfu<'a, B: UsbBus> {
     woosh: PhantomData<B>,
     comm_if: InterfaceNumber,
-    def_str: StringIndex,
+    alt_str: [StringIndex; memory::ALT_SETTINGS.len()],
+    layouts: [util::StrBuf; memory::ALT_SETTINGS.len()],
+    alt: u8,
+    memory: Memory,
     upload_capable: bool,
     download_capable: bool,
-    state: DfuState,
-    status: DfuDeviceStatus,
+    machine: DfuStateMachine,
     firmware_size: usize,
+    address_pointer: u32,
+    command: core::option::Option<DfuseCommand>,
     awaits_flash: bool,
     flashing: bool,
-    manifesting: bool,
+    manifested: bool,
     page_buffer: [u8; BIGGEST_PAGE],
     page_buffer_index: usize,
+    page_addr: u32,
     flags: core::option::Option<&'a flags::BlFlags>,
 }
 
 impl<B: UsbBus> Dfu<'_, B> {
-    pub fn new(alloc: &UsbBusAllocator<B>, download_capable: bool, tx: Option<Tx<USART1>>) -> Dfu<'_, B> {
+    pub fn new(alloc: &UsbBusAllocator<B>, download_capable: bool, upload_capable: bool,
+               tx: Option<Tx<USART1>>) -> Dfu<'_, B> {
         unsafe { LOGGER = tx }
         let flags = flags::read_bl_flags();
+        let alts = &memory::ALT_SETTINGS;
         Dfu {
             woosh: PhantomData,
             comm_if: alloc.interface(),
-            def_str: alloc.string(),
-            upload_capable: false,
-            download_capable: download_capable,
-            state: DfuState::DfuIdle,
-            status: DfuDeviceStatus::Ok,
+            alt_str: [alloc.string(), alloc.string(), alloc.string(), alloc.string()],
+            layouts: [alts[0].layout(), alts[1].layout(), alts[2].layout(), alts[3].layout()],
+            alt: 0,
+            memory: Memory::Application,
+            upload_capable,
+            download_capable,
+            machine: DfuStateMachine::new(config::MANIFESTATION_TOLERANT),
             firmware_size: 0,
+            address_pointer: flash::PAGE_START,
+            command: None,
             awaits_flash: false,
             flashing: false,
-            manifesting: false,
+            manifested: false,
             page_buffer: unsafe { mem::zeroed() },
             page_buffer_index: 0,
-            flags: flags,
+            page_addr: flash::PAGE_START,
+            flags,
         }
     }
 
@@ -121,14 +120,147 @@ impl<B: UsbBus> Dfu<'_, B> {
         self.flags
     }
 
-    pub fn process_flash(&mut self) {
-        if self.awaits_flash && !self.flashing {
-            self.flashing = true;
-            cortex_m::interrupt::free(|_| {
+    // end of readable data in the selected memory
+    fn upload_end(&self) -> u32 {
+        match self.memory {
+            Memory::Application => match self.flags() {
+                Some(flags) if flags.user_code_present =>
+                    flash::PAGE_START + flags.user_code_length,
+                _ => flash::PAGE_START,
+            },
+            _ => self.memory.end(),
+        }
+    }
+
+    pub fn get_alternate_setting(&self) -> u8 {
+        self.alt
+    }
+
+    pub fn set_alternate_setting(&mut self, alt: u8) -> bool {
+        match self.machine.state() {
+            DfuState::DfuIdle | DfuState::DfuError => {},
+            _ => return false,
+        }
+        match Memory::from_alt(alt) {
+            Some(memory) => {
+                self.alt = alt;
+                self.memory = memory;
+                self.address_pointer = memory.start();
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn dnload_command(&mut self, data: &[u8]) -> core::result::Result<(), DfuDeviceStatus> {
+        let addr = if data.len() == 5 {
+            Some(u32::from_le_bytes([data[1], data[2], data[3], data[4]]))
+        } else { None };
+        match (data[0], addr) {
+            (dfuse_command::SET_ADDRESS_POINTER, Some(addr)) => {
+                self.address_pointer = addr;
+                Ok(())
+            },
+            (dfuse_command::ERASE, _) if !self.memory.erasable() => {
+                Err(DfuDeviceStatus::ErrErase)
+            },
+            (dfuse_command::ERASE, Some(addr)) => {
+                if !self.memory.contains(addr) {
+                    return Err(DfuDeviceStatus::ErrAddress);
+                }
+                self.command = Some(DfuseCommand::Erase(addr));
+                Ok(())
+            },
+            (dfuse_command::ERASE, None) if data.len() == 1 => {
+                self.command = Some(DfuseCommand::MassErase);
+                Ok(())
+            },
+            (dfuse_command::READ_UNPROTECT, None) if data.len() == 1 => {
+                self.command = Some(DfuseCommand::ReadUnprotect);
+                Ok(())
+            },
+            _ => Err(DfuDeviceStatus::ErrStaledPkt),
+        }
+    }
+
+    fn dnload_block(&mut self, block: u16, data: &[u8]) -> core::result::Result<(), DfuDeviceStatus> {
+        let addr = self.address_pointer + (block as u32 - 2) * TRANSFER_SIZE as u32;
+        let len = data.len();
+        if !self.memory.writable() {
+            return Err(DfuDeviceStatus::ErrWrite);
+        }
+        if !self.memory.contains(addr) {
+            return Err(DfuDeviceStatus::ErrAddress);
+        }
+        if let Memory::OptionBytes = self.memory {
+            // option bytes are erased and programmed as a whole block
+            if addr != self.memory.start() || addr + len as u32 != self.memory.end() {
+                return Err(DfuDeviceStatus::ErrAddress);
+            }
+        }
+        self.page_buffer[..len].copy_from_slice(data);
+        self.page_buffer_index = len;
+        self.page_addr = addr;
+        self.awaits_flash = true;
+        Ok(())
+    }
+
+    fn upload_data(&self, block: u16, length: u16) -> core::result::Result<&'static [u8], DfuDeviceStatus> {
+        match block {
+            0 => {
+                let len = core::cmp::min(length as usize, DFUSE_COMMANDS.len());
+                Ok(&DFUSE_COMMANDS[..len])
+            },
+            1 => Err(DfuDeviceStatus::ErrStaledPkt),
+            _ => {
+                let addr = self.address_pointer + (block as u32 - 2) * length as u32;
+                if addr < self.memory.start() {
+                    return Err(DfuDeviceStatus::ErrAddress);
+                }
+                let len = core::cmp::min(length as u32,
+                                         self.upload_end().saturating_sub(addr));
+                Ok(unsafe { flash::read(addr, len as usize) })
+            },
+        }
+    }
+
+    fn run_command(&mut self, command: DfuseCommand) {
+        cortex_m::interrupt::free(|_| {
+            unsafe {
+                match command {
+                    DfuseCommand::Erase(addr) => {
+                        util::_log_fmt(format_args!("Erasing page 0x{:x}\r\n", addr));
+                        flash::erase_page(addr);
+                    },
+                    DfuseCommand::MassErase => {
+                        util::_log_str("Erasing user code\r\n");
+                        let page_size = flash::get_flash_pg_size() as u32;
+                        let mut addr = Memory::Application.start();
+                        while addr < Memory::Application.end() {
+                            flash::erase_page(addr);
+                            addr += page_size;
+                        }
+                    },
+                    DfuseCommand::ReadUnprotect => flash::read_unprotect(),
+                }
+            }
+        });
+    }
+
+    fn program_option_bytes(&mut self) -> core::result::Result<(), DfuDeviceStatus> {
+        let data = &self.page_buffer[..self.page_buffer_index];
+        let result = cortex_m::interrupt::free(|_| unsafe { flash::write_option_bytes(data) });
+        self.page_buffer_index = 0;
+        result.map_err(|_| {
+            util::_log_str("Option bytes write failed\r\n");
+            DfuDeviceStatus::ErrProg
+        })
+    }
+
+    fn program(&mut self) -> core::result::Result<(), DfuDeviceStatus> {
+        cortex_m::interrupt::free(|_| {
             unsafe {
-                let mut addr: u32 = flash::PAGE_START +
-                    self.firmware_size as u32;
-                flash::erase_page(addr);
+                let mut addr: u32 = self.page_addr;
                 let n: usize = (self.page_buffer_index) / 4;
                 for i in 0..n {
                     let d: u32 = u32::from_le_bytes(
@@ -136,46 +268,85 @@ impl<B: UsbBus> Dfu<'_, B> {
                         self.page_buffer[(i*4)+2], self.page_buffer[(i*4)+3]]);
                     match flash::write_word(addr, d) {
                         Ok(_) => {
-                            self.status = DfuDeviceStatus::Ok;
                             addr += 4;
                         },
                         Err(_) => {
                             util::_log_fmt(format_args!("Write failed on i: {}  addr: 0x{:x} sr: 0x{:x}\r\n", i, addr, &(*(FLASH::ptr())).sr.read().bits()));
-                            self.status = DfuDeviceStatus::ErrWrite;
-                            
                             self.page_buffer_index = 0;
-                            self.awaits_flash = false;
-                            self.flashing = false;
-                            return;
+                            return Err(DfuDeviceStatus::ErrWrite);
                         },
                     }
                 }
-                self.firmware_size += self.page_buffer_index;
+                let end = (self.page_addr - flash::PAGE_START) as usize
+                    + self.page_buffer_index;
+                if end > self.firmware_size {
+                    self.firmware_size = end;
+                }
                 self.page_buffer_index = 0;
+                Ok(())
             }
-            });
-            self.awaits_flash = false;
-            self.flashing = false;
-        }
-        else if self.manifesting && !self.flashing {
-            self.flashing = true;
-            let flash_count: u32 = match self.flags() {
-                Some(flags) => flags.flash_count+1,
-                None => 1,
-            };
-            let flags = &flags::BlFlags {
-                magic: BL_MAGIC,
-                flash_count: flash_count,
-                user_code_legit: true,
-                user_code_present: true,
-                user_code_length: self.firmware_size as u32,
-            };
-            flags::write_bl_flags(flags);
-            self.flags = flags::read_bl_flags();
+        })
+    }
+
+    fn manifest(&mut self) -> core::result::Result<(), DfuDeviceStatus> {
+        self.manifested = true;
+        if self.firmware_size == 0 {
+            // nothing was downloaded, e.g. a bare DfuSe leave request
             unsafe { flash::lock_flash(); }
-            self.manifesting = false;
-            self.flashing = false;
+            return Ok(());
+        }
+        let flash_count: u32 = match self.flags() {
+            Some(flags) => flags.flash_count+1,
+            None => 1,
+        };
+        let flags = &flags::BlFlags {
+            magic: BL_MAGIC,
+            flash_count,
+            user_code_legit: true,
+            user_code_present: true,
+            user_code_length: self.firmware_size as u32,
+        };
+        flags::write_bl_flags(flags);
+        self.flags = flags::read_bl_flags();
+        self.firmware_size = 0;
+        unsafe { flash::lock_flash(); }
+        Ok(())
+    }
+
+    pub fn process_flash(&mut self) {
+        if let DfuState::DfuManifestWaitReset = self.machine.state() {
+            if config::WILL_DETACH {
+                unsafe { util::leave_bootloader(); }
+            }
+            return;
+        }
+        if !self.machine.busy() || self.flashing {
+            return;
         }
+        self.flashing = true;
+        let result = match self.machine.state() {
+            DfuState::DfuManifest => self.manifest(),
+            _ => {
+                if let Some(command) = self.command.take() {
+                    self.run_command(command);
+                }
+                if self.awaits_flash {
+                    self.awaits_flash = false;
+                    match self.memory {
+                        Memory::OptionBytes => self.program_option_bytes(),
+                        _ => self.program(),
+                    }
+                }
+                else {
+                    Ok(())
+                }
+            },
+        };
+        self.machine.handle(match result {
+            Ok(_) => Event::FlashDone,
+            Err(status) => Event::FlashFailed(status),
+        });
+        self.flashing = false;
     }
 }
 
@@ -195,35 +366,58 @@ impl<B:UsbBus> UsbClass<B> for Dfu<'_, B> {
         ])
     }
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
-        writer.interface_alt(self.comm_if, 0,
-                            CLASS_APPLICATION_SPECIFIC,
-                            SUBCLASS_DFU,
-                            PROTOCOL_DFU_MODE,
-                            Some(self.def_str))?;
+        for (alt, string) in self.alt_str.iter().enumerate() {
+            writer.interface_alt(self.comm_if, alt as u8,
+                                CLASS_APPLICATION_SPECIFIC,
+                                SUBCLASS_DFU,
+                                PROTOCOL_DFU_MODE,
+                                Some(*string))?;
+        }
 
         writer.write(DESC_DFU_FUNCTIONAL, &[
-                     0x4 // manifestation tolerant
+                     if config::WILL_DETACH { 0x08 } else { 0x00 }
+                        | if config::MANIFESTATION_TOLERANT { 0x04 } else { 0x00 }
                         | if self.upload_capable { 0x02 } else { 0x00 }
                         | if self.download_capable { 0x01 } else { 0x00 }, //bmAttributes
                      255, 0, // wDetachTimeout
-                     //(page_size & 0xff) as u8,
-                     //((page_size >> 8) & 0xff) as u8, // wTransferSize
-                     0x00, 0x01, // 256 bytes max
-                     0x10, 0x01, // bcdDFUVersion
+                     (TRANSFER_SIZE & 0xff) as u8,
+                     ((TRANSFER_SIZE >> 8) & 0xff) as u8, // wTransferSize
+                     0x1a, 0x01, // bcdDFUVersion, DfuSe
                      ])?;
         Ok(())
     }
 
-    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
-        if index == self.def_str {
-            Some(config::DFU_AL0)
-        } else {
-            None
+    fn reset(&mut self) {
+        // dfu-util -R, or the host completing a manifestation without detach
+        let leave = match self.machine.state() {
+            DfuState::DfuManifestWaitReset => true,
+            _ => self.manifested,
+        };
+        if leave {
+            unsafe { util::leave_bootloader(); }
         }
+        self.machine.handle(Event::UsbReset);
+        self.command = None;
+        self.awaits_flash = false;
+        self.page_buffer_index = 0;
+    }
+
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        self.alt_str.iter()
+            .position(|s| *s == index)
+            .map(|alt| self.layouts[alt].as_str())
     }
 
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = *xfer.request();
+        // usb-device does not track alternate settings, answer for our interface here
+        if req.request_type == control::RequestType::Standard
+            && req.recipient == control::Recipient::Interface
+            && req.request == control::Request::GET_INTERFACE
+            && req.index == u8::from(self.comm_if) as u16 {
+            xfer.accept_with(&[self.get_alternate_setting()]).ok();
+            return;
+        }
         if !(req.request_type == control::RequestType::Class
              && req.recipient == control::Recipient::Interface
              && req.index == u8::from(self.comm_if) as u16) {
@@ -232,77 +426,56 @@ impl<B:UsbBus> UsbClass<B> for Dfu<'_, B> {
 
         fn accept_status<B: UsbBus> (xfer: ControlIn<B>, c: &Dfu<B>, wait_time_ms: u32) {
             xfer.accept_with(&[
-                             c.status as u8,
+                             c.machine.status() as u8,
                              (wait_time_ms & 0xff) as u8,
                              ((wait_time_ms >> 8) & 0xff) as u8,
                              ((wait_time_ms >> 16) & 0xff) as u8,
-                             c.state as u8,
+                             c.machine.state() as u8,
                              0,
             ]).ok();
         }
 
-
-        match self.status {
-            DfuDeviceStatus::Ok => {},
-            _ => {
-                self.state = DfuState::DfuError;
-            },
-        }
-
-        match self.state {
-            DfuState::DfuDnloadBusy => {
-                if !self.awaits_flash {
-                    self.state = DfuState::DfuDnloadSync;
-                }
-            },
-            _ => {},
-        }
-
         match req.request {
-            dfu_request::DFU_UPLOAD if req.value == 0
-                && req.length > 0
+            dfu_request::DFU_UPLOAD if req.length > 0
                 && self.upload_capable => {
-            },
-            dfu_request::DFU_GETSTATUS if req.value == 0
-                && req.length == 6 => {
-                    match self.state {
-                        DfuState::DfuDnloadSync => {
-                            if self.awaits_flash {
-                                self.state = DfuState::DfuDnloadBusy;
+                    match self.upload_data(req.value, req.length) {
+                        Ok(data) => {
+                            // a short packet ends the upload
+                            let short = data.len() < req.length as usize;
+                            if self.machine.handle(Event::Upload { short }) {
+                                xfer.accept_with(data).ok();
                             }
                             else {
-                                self.state = DfuState::DfuDnloadIdle;
+                                xfer.reject().ok();
                             }
-                            self.status = DfuDeviceStatus::Ok;
-                            accept_status(xfer, &self, 0);
                         },
-                        DfuState::DfuDnloadBusy => {
-                            self.state = DfuState::DfuDnloadSync;
-                            accept_status(xfer, &self, 500);
+                        Err(status) => {
+                            self.machine.handle(Event::Stall(status));
+                            xfer.reject().ok();
                         },
-                        DfuState::DfuManifest => {
-                            if self.manifesting {
-                                accept_status(xfer, &self, 500);
-                            }
-                            else {
-                                self.state = DfuState::DfuManifestSync;
-                                accept_status(xfer, &self, 0);
-                            }
-                        },
-                        DfuState::DfuManifestSync => {
-                            self.state = DfuState::DfuIdle;
-                            accept_status(xfer, &self, 0);
-                        },
-                        _ => accept_status(xfer, &self, 0),
+                    }
+            },
+            dfu_request::DFU_GETSTATUS if req.value == 0
+                && req.length == 6 => {
+                    if self.machine.handle(Event::GetStatus) {
+                        let wait_time_ms = if self.machine.busy() { 500 } else { 0 };
+                        accept_status(xfer, self, wait_time_ms);
+                    }
+                    else {
+                        xfer.reject().ok();
                     }
             },
             dfu_request::DFU_GETSTATE if req.value == 0
                 && req.length == 1 => {
-                    xfer.accept_with(&[ self.state as u8 ]).ok();
+                    if self.machine.handle(Event::GetState) {
+                        xfer.accept_with(&[ self.machine.state() as u8 ]).ok();
+                    }
+                    else {
+                        xfer.reject().ok();
+                    }
             },
             _ => {
-                self.state = DfuState::DfuError;
-                self.status = DfuDeviceStatus::ErrStaledPkt;
+                self.machine.handle(Event::Stall(DfuDeviceStatus::ErrStaledPkt));
                 util::_log_fmt(format_args!("Stalled pkt  req: {:?}\r\n", req));
                 xfer.reject().ok();
             },
@@ -311,6 +484,18 @@ impl<B:UsbBus> UsbClass<B> for Dfu<'_, B> {
 
     fn control_out<'a>(&mut self, xfer: ControlOut<B>) {
         let req = *xfer.request();
+        if req.request_type == control::RequestType::Standard
+            && req.recipient == control::Recipient::Interface
+            && req.request == control::Request::SET_INTERFACE
+            && req.index == u8::from(self.comm_if) as u16 {
+            if self.set_alternate_setting(req.value as u8) {
+                xfer.accept().ok();
+            }
+            else {
+                xfer.reject().ok();
+            }
+            return;
+        }
         if !(req.request_type == control::RequestType::Class
              && req.recipient == control::Recipient::Interface
              && req.index == u8::from(self.comm_if) as u16) {
@@ -319,57 +504,49 @@ impl<B:UsbBus> UsbClass<B> for Dfu<'_, B> {
 
         match req.request {
             dfu_request::DFU_DNLOAD if self.download_capable => {
+                    if !self.machine.handle(Event::Dnload { length: req.length }) {
+                        xfer.reject().ok();
+                        return;
+                    }
                     if req.length > 0 {
-                        match self.state {
-                            DfuState::DfuIdle | DfuState::DfuDnloadIdle => {
-                                unsafe{ flash::unlock_flash(); }
-                                let start = self.page_buffer_index;
-                                //let len = req.length as usize;
-                                let len = xfer.data().len();
-                                let page_size = unsafe { flash::get_flash_pg_size() };
-                                self.page_buffer[start..start+len]
-                                    .copy_from_slice(&xfer.data()[..len]);
-                                self.page_buffer_index = start + len;
-                                if self.page_buffer_index == page_size as usize {
-                                    self.awaits_flash = true;
-                                }
-                                self.state = DfuState::DfuDnloadSync;
-                                xfer.accept().ok();
+                        unsafe{ flash::unlock_flash(); }
+                        let accepted = match req.value {
+                            0 => self.dnload_command(xfer.data()),
+                            1 => Err(DfuDeviceStatus::ErrStaledPkt),
+                            _ => self.dnload_block(req.value, xfer.data()),
+                        };
+                        match accepted {
+                            Ok(_) => {xfer.accept().ok();},
+                            Err(status) => {
+                                self.machine.handle(Event::Stall(status));
+                                xfer.reject().ok();
                             },
-                            _ => {xfer.reject().ok();},
                         }
                     }
                     else {
-                        self.state = DfuState::DfuManifest;
-                        self.manifesting = true;
-                        self.awaits_flash = true;
                         xfer.accept().ok();
                     }
             },
             dfu_request::DFU_CLRSTATUS if req.value == 0
                 && req.length == 0 => {
-                    match self.state {
-                        DfuState::DfuError => {
-                            self.state = DfuState::DfuIdle;
-                            self.status = DfuDeviceStatus::Ok;
-                            xfer.accept().ok();
-                        },
-                        _ => {xfer.reject().ok();},
+                    if self.machine.handle(Event::ClrStatus) {
+                        xfer.accept().ok();
+                    }
+                    else {
+                        xfer.reject().ok();
                     }
             },
             dfu_request::DFU_ABORT if req.value == 0
                 && req.length == 0 => {
-                    match self.state {
-                        DfuState::DfuIdle => {
-                            self.status = DfuDeviceStatus::Ok;
-                            xfer.accept().ok();
-                        },
-                        _ => {xfer.reject().ok();}
+                    if self.machine.handle(Event::Abort) {
+                        xfer.accept().ok();
+                    }
+                    else {
+                        xfer.reject().ok();
                     }
             },
             _ => {
-                self.state = DfuState::DfuError;
-                self.status = DfuDeviceStatus::ErrStaledPkt;
+                self.machine.handle(Event::Stall(DfuDeviceStatus::ErrStaledPkt));
                 xfer.reject().ok();
             },
         }
@@ -9,6 +9,16 @@ const BL_FLAGS_HIGH: u32 = 0x0801fc00;
 #[allow(dead_code)]
 const BL_FLAGS_LOW: u32 = 0x0800fc00;
 
+// first byte past the application region
+pub(crate) fn bl_flags_addr() -> u32 {
+    if unsafe { flash::get_flash_size_kb() } >= 128 {
+        BL_FLAGS_HIGH
+    }
+    else {
+        BL_FLAGS_LOW
+    }
+}
+
 pub(crate) fn write_bl_flags(flags: &BlFlags) {
     unsafe fn _write(flags: &BlFlags, addr: u32) {
         util::_log_fmt(format_args!("Writing BL FLAGS to 0x{:x}\r\n", addr));
@@ -42,16 +52,16 @@ pub(crate) fn read_bl_flags() -> core::option::Option<&'static BlFlags> {
             flags = &*(BL_FLAGS_LOW as *mut BlFlags);
             if flags.magic != dfu::BL_MAGIC {
                 util::_log_str("Magic in BL_FLAGS_LOW not found\r\n");
-                return None;
+                None
             }
             else {
                 util::_log_fmt(format_args!("Flags from BL_FLAGS_LOW: {}\r\n", flags));
-                return Some(flags);
+                Some(flags)
             }
         }
         else {
             util::_log_fmt(format_args!("Flags from BL_FLAGS_HIGH: {}\r\n", flags));
-            return Some(flags);
+            Some(flags)
         }
     }
 }
@@ -3,17 +3,27 @@ use stm32f1xx_hal::pac::FLASH;
 
 pub(crate) const FLASH_PAGESIZE: u32 = 0x1FFFF7E0;
 pub(crate) const PAGE_START: u32 = 0x08004800;
+pub(crate) const OPTION_BYTES: u32 = 0x1FFFF800;
+const RDP_KEY: u8 = 0xA5;
+
+pub(crate) unsafe fn get_flash_size_kb() -> u16 {
+    (core::ptr::read_volatile(FLASH_PAGESIZE as *const u32) & 0xffff) as u16
+}
 
 pub(crate) unsafe fn get_flash_pg_size() -> u16 {
-    let r = core::ptr::read_volatile(FLASH_PAGESIZE as *const u32) & 0xffff;
+    let r = get_flash_size_kb();
     if r > 128 {
-        return 0x800;
+        0x800
     }
     else {
-        return 0x400;
+        0x400
     }
 }
 
+pub(crate) unsafe fn read(addr: u32, len: usize) -> &'static [u8] {
+    core::slice::from_raw_parts(addr as *const u8, len)
+}
+
 pub(crate) unsafe fn unlock_flash() {
     let flash = &*FLASH::ptr();
 
@@ -69,3 +79,90 @@ pub(crate) unsafe fn erase_page(addr: u32) {
     while flash.sr.read().bsy().bit_is_set() {}
     flash.cr.write(|w| w.bits(0));
 }
+
+unsafe fn unlock_option_bytes() {
+    let flash = &*FLASH::ptr();
+
+    flash.optkeyr.write(|w| w.optkey().bits(0x45670123));
+    flash.optkeyr.write(|w| w.optkey().bits(0xCDEF89AB));
+}
+
+// clears the error flags, which are write 1 to clear, ahead of an operation
+unsafe fn clear_errors(flash: &stm32f1xx_hal::pac::flash::RegisterBlock) {
+    flash.sr.write(|w| w.eop().set_bit().pgerr().set_bit().wrprterr().set_bit());
+}
+
+unsafe fn check_errors(flash: &stm32f1xx_hal::pac::flash::RegisterBlock) -> core::result::Result<(), ()> {
+    let sr = flash.sr.read();
+    if sr.pgerr().bit_is_set() || sr.wrprterr().bit_is_set() {
+        Err(())
+    }
+    else {
+        Ok(())
+    }
+}
+
+unsafe fn erase_option_bytes() -> core::result::Result<(), ()> {
+    let flash = &*FLASH::ptr();
+
+    while flash.sr.read().bsy().bit_is_set() {}
+    clear_errors(flash);
+    flash.cr.modify(|_, w| w.opter().bit(true));
+    flash.cr.modify(|_, w| w.strt().bit(true).opter().bit(true));
+    while flash.sr.read().bsy().bit_is_set() {}
+    flash.cr.modify(|_, w| w.opter().bit(false).strt().bit(false));
+    check_errors(flash)
+}
+
+unsafe fn write_option_byte(addr: u32, data: u8) -> core::result::Result<(), ()> {
+    let flash = &*FLASH::ptr();
+
+    clear_errors(flash);
+    flash.cr.modify(|_, w| w.optpg().bit(true));
+    core::ptr::write_volatile(addr as *mut u16, data as u16);
+    while flash.sr.read().bsy().bit_is_set() {}
+    flash.cr.modify(|_, w| w.optpg().bit(false));
+    check_errors(flash)?;
+
+    // hardware stores the complement in the upper byte
+    if core::ptr::read_volatile(addr as *const u16) == u16::from_le_bytes([data, !data]) {
+        Ok(())
+    }
+    else {
+        Err(())
+    }
+}
+
+// data is the whole option byte block as read back, byte/complement pairs;
+// values take effect after the next reset. Bytes sent as 0xff stay erased,
+// except RDP: an erased RDP/nRDP pair reads as read protection, so a block
+// without RDP is refused before anything is erased.
+pub(crate) unsafe fn write_option_bytes(data: &[u8]) -> core::result::Result<(), ()> {
+    if data[0] == 0xff {
+        return Err(());
+    }
+    unlock_option_bytes();
+    erase_option_bytes()?;
+    for (i, pair) in data.chunks(2).enumerate() {
+        let addr = OPTION_BYTES + (i as u32 * 2);
+        if pair[0] != 0xff {
+            write_option_byte(addr, pair[0])?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) unsafe fn read_unprotect() {
+    let flash = &*FLASH::ptr();
+
+    if flash.obr.read().rdprt().bit_is_clear() {
+        return;
+    }
+
+    // removing RDP triggers a mass erase of the whole flash, bootloader included
+    unlock_option_bytes();
+    erase_option_bytes().ok();
+    write_option_byte(OPTION_BYTES, RDP_KEY).ok();
+
+    cortex_m::peripheral::SCB::sys_reset();
+}
@@ -1,6 +1,8 @@
 #![no_std]
 #![no_main]
-#![feature(asm)]
+// the code RTIC 0.5's app macro expands to: resources are static muts, it
+// implements traits inside of functions and checks cfg(core)
+#![allow(static_mut_refs, non_local_definitions, unexpected_cfgs)]
 
 use panic_halt as _;
 
@@ -31,11 +33,13 @@ use usb_device::{
 
 use usbd_webusb::*;
 
+mod boot;
 mod dfu;
 mod flags;
 mod flash;
 mod util;
 mod config;
+mod memory;
 
 use crate::dfu::*;
 
@@ -75,18 +79,6 @@ unsafe impl UsbPeripheral for Peripheral {
 
 pub type UsbBusType = UsbBus<Peripheral>;
 
-pub(crate) fn check_sw_int() -> bool {
-    unsafe {
-        let rcc = &*RCC::ptr();
-        if rcc.csr.read().sftrstf().bit_is_set() {
-            return true;
-        }
-        else {
-            return false;
-        }
-    }
-}
-
 #[app(device = stm32f1xx_hal::pac, peripherals = true)]
 const APP: () = {
     struct Resources {
@@ -119,11 +111,6 @@ const APP: () = {
         let mut gpioa = device.GPIOA.split(&mut rcc.apb2);
         let mut gpioc = device.GPIOC.split(&mut rcc.apb2);
 
-        //if !(gpioc.pc14.is_high().ok().unwrap() || check_sw_int()) {
-        //    // will fail if user code is not present or legit
-        //    unsafe { jump_to_usercode(); }
-        //}
-
         let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
         usb_dp.set_low().unwrap();
         delay(clocks.sysclk().0 / 100);
@@ -156,26 +143,27 @@ const APP: () = {
         } else { None };
 
 
-        let dfu = Dfu::new(USB_BUS.as_ref().unwrap(), true, tx);
-        if !(gpioc.pc14.is_high().ok().unwrap() || check_sw_int()) {
+        let dfu = Dfu::new(USB_BUS.as_ref().unwrap(), true, true, tx);
+        let run_user_code = match boot::take_boot_request() {
+            // reset issued by the bootloader itself after a download
+            boot::BootRequest::Application => true,
+            // dfu_runtime::reboot_into_bootloader() from the user code
+            boot::BootRequest::Bootloader => false,
+            boot::BootRequest::None => !gpioc.pc14.is_high().ok().unwrap(),
+        };
+        if run_user_code {
             // will fail if user code is not present or legit
             unsafe { util::jump_to_usercode(); }
             util::_log_str("User Code not present: Entering bootloader\r\n");
         }
         else {
-            util::_log_str("Button pressed or Software Reboot: Entering bootloader\r\n");
+            util::_log_str("Button pressed or DFU requested: Entering bootloader\r\n");
         }
 
         let wusb = WebUsb::new(USB_BUS.as_ref().unwrap(), url_scheme::HTTPS,
                             config::WEBUSB_URL);
 
-        let mut blinks = 2;
-        match dfu.flags() {
-            Some(_) => {
-                blinks = 4;
-            },
-            None => {}
-        }
+        let blinks = if dfu.flags().is_some() { 4 } else { 2 };
 
         let led = gpioc.pc13.into_push_pull_output_with_state(&mut gpioc.crh,
                                                                   State::High);
@@ -211,7 +199,7 @@ const APP: () = {
 
     #[task(binds = TIM1_UP, priority = 1, resources = [LED, BLINK, LED_STATE, TIMER_HANDLE])]
     fn tim1_up(c: tim1_up::Context) {
-        if *c.resources.BLINK % 2 == 0 {
+        if *c.resources.BLINK & 1 == 0 {
             if *c.resources.LED_STATE {
                 c.resources.LED.set_high().unwrap();
                 *c.resources.LED_STATE = false;
@@ -230,13 +218,13 @@ const APP: () = {
     }
 
     #[task(binds = USB_HP_CAN_TX, priority = 1, resources = [USB_DEV, DFU, WUSB])]
-    fn USB_HP_CAN_TX(mut c: USB_HP_CAN_TX::Context) {
-        usb_poll(&mut c.resources.USB_DEV, &mut c.resources.DFU, &mut c.resources.WUSB);
+    fn USB_HP_CAN_TX(c: USB_HP_CAN_TX::Context) {
+        usb_poll(c.resources.USB_DEV, c.resources.DFU, c.resources.WUSB);
     }
 
     #[task(binds = USB_LP_CAN_RX0, priority = 1, resources = [USB_DEV, DFU, WUSB])]
-    fn USB_LP_CAN_RX0(mut c: USB_LP_CAN_RX0::Context) {
-        usb_poll(&mut c.resources.USB_DEV, &mut c.resources.DFU, &mut c.resources.WUSB);
+    fn USB_LP_CAN_RX0(c: USB_LP_CAN_RX0::Context) {
+        usb_poll(c.resources.USB_DEV, c.resources.DFU, c.resources.WUSB);
     }
 };
 
@@ -0,0 +1,96 @@
+use core::fmt::Write;
+use crate::config;
+use crate::flags;
+use crate::flash;
+use crate::util;
+
+const RAM_START: u32 = 0x20000000;
+const OPTION_BYTES_SIZE: u32 = 16;
+
+// Memories exposed as DFU alternate settings, in alt setting order
+#[derive(Copy, Clone)]
+pub(crate) enum Memory {
+    Application,
+    Flags,
+    OptionBytes,
+    Ram,
+}
+
+pub(crate) const ALT_SETTINGS: [Memory; 4] = [
+    Memory::Application,
+    Memory::Flags,
+    Memory::OptionBytes,
+    Memory::Ram,
+];
+
+impl Memory {
+    pub(crate) fn from_alt(alt: u8) -> core::option::Option<Memory> {
+        ALT_SETTINGS.get(alt as usize).copied()
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Memory::Application => config::DFU_AL0,
+            Memory::Flags => config::DFU_AL1,
+            Memory::OptionBytes => config::DFU_AL2,
+            Memory::Ram => config::DFU_AL3,
+        }
+    }
+
+    pub(crate) fn start(&self) -> u32 {
+        match self {
+            Memory::Application => flash::PAGE_START,
+            Memory::Flags => flags::bl_flags_addr(),
+            Memory::OptionBytes => flash::OPTION_BYTES,
+            Memory::Ram => RAM_START,
+        }
+    }
+
+    pub(crate) fn end(&self) -> u32 {
+        match self {
+            Memory::Application => flags::bl_flags_addr(),
+            Memory::Flags => flags::bl_flags_addr() + 0x400,
+            Memory::OptionBytes => flash::OPTION_BYTES + OPTION_BYTES_SIZE,
+            Memory::Ram => RAM_START + config::RAM_SIZE,
+        }
+    }
+
+    fn sector_size(&self) -> u32 {
+        match self {
+            Memory::Application => unsafe { flash::get_flash_pg_size() as u32 },
+            Memory::Flags | Memory::Ram => 0x400,
+            Memory::OptionBytes => OPTION_BYTES_SIZE,
+        }
+    }
+
+    pub(crate) fn contains(&self, addr: u32) -> bool {
+        addr >= self.start() && addr < self.end()
+    }
+
+    pub(crate) fn writable(&self) -> bool {
+        matches!(self, Memory::Application | Memory::OptionBytes)
+    }
+
+    pub(crate) fn erasable(&self) -> bool {
+        matches!(self, Memory::Application)
+    }
+
+    // DfuSe attribute: a = read, e = read/write, g = read/erase/write
+    fn attributes(&self) -> char {
+        if self.erasable() { 'g' } else if self.writable() { 'e' } else { 'a' }
+    }
+
+    pub(crate) fn layout(&self) -> util::StrBuf {
+        let mut layout = util::StrBuf::new();
+        let sector = self.sector_size();
+        let count = (self.end() - self.start()) / sector;
+        write!(layout, "{}/0x{:08x}/{:02}*", self.name(), self.start(), count).ok();
+        if sector >= 1024 {
+            write!(layout, "{}K{}", sector / 1024, self.attributes()).ok();
+        }
+        else {
+            write!(layout, "{:03} {}", sector, self.attributes()).ok();
+        }
+        layout
+    }
+}
@@ -1,11 +1,13 @@
 use cortex_m::peripheral::{SCB, NVIC};
+use cortex_m::asm::delay;
 use core::fmt::Write;
 use stm32f1xx_hal::{
-    pac::{STK, RCC, USART1},
+    pac::{STK, RCC, GPIOA, USART1},
     serial::Tx,
 };
 use crate::flash;
 use crate::flags;
+use crate::boot;
 
 pub(crate) static mut LOGGER: Option<Tx<USART1>> = None;
 
@@ -24,46 +26,84 @@ pub(crate) fn _log_fmt(args: core::fmt::Arguments) {
     }
 }
 
+pub(crate) struct StrBuf {
+    buf: [u8; 64],
+    len: usize,
+}
+
+impl StrBuf {
+    pub(crate) const fn new() -> StrBuf {
+        StrBuf { buf: [0; 64], len: 0 }
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for StrBuf {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(core::fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
 pub(crate) unsafe fn jump_to_usercode() {
     let scb = &*SCB::ptr();
     let nvic = &*NVIC::ptr();
     let stk = &*STK::ptr();
     let rcc = &*RCC::ptr();
-    match flags::read_bl_flags() {
-        Some(flags) => {
-            if flags.user_code_present {
-                cortex_m::interrupt::free(|_| {
-                    _log_str("Jumping to User Code\r\n");
-                    const STACK_POINTER: u32 = flash::PAGE_START;
-                    const ENTRY_POINT: u32 = flash::PAGE_START+4;
+    if !flags::read_bl_flags().is_some_and(|flags| flags.user_code_present) {
+        return;
+    }
+    cortex_m::interrupt::free(|_| {
+        _log_str("Jumping to User Code\r\n");
 
-                    let user_msp = core::ptr::read_volatile(STACK_POINTER as *const u32);
-                    let user_jmp = core::ptr::read_volatile(ENTRY_POINT as *const u32);
-                    let offset: u32 = flash::PAGE_START - 0x08000000;
+        let offset: u32 = flash::PAGE_START - 0x08000000;
 
-                    //disable interrupts
-                    nvic.icer[0].write(0xffffffff);
-                    nvic.icer[1].write(0xffffffff);
-                    nvic.icpr[0].write(0xffffffff);
-                    nvic.icpr[1].write(0xffffffff);
-                    //disable systick
-                    stk.ctrl.modify(|_, w| w.enable().bit(false));
-                    //reset clocks
-                    rcc.cr.modify(|_, w| w.hsion().bit(true));
-                    rcc.cfgr.modify(|r, w| w.bits(r.bits() & 0xf8ff0000));
-                    rcc.cr.modify(|r, w| w.bits(r.bits() & 0xfef6ffff));
-                    rcc.cr.modify(|r, w| w.bits(r.bits() & 0xfffbffff));
-                    rcc.cfgr.modify(|r, w| w.bits(r.bits() & 0xff80ffff));
-                    rcc.cir.write(|w| w.bits(0));
+        //disable interrupts
+        nvic.icer[0].write(0xffffffff);
+        nvic.icer[1].write(0xffffffff);
+        nvic.icpr[0].write(0xffffffff);
+        nvic.icpr[1].write(0xffffffff);
+        //disable systick
+        stk.ctrl.modify(|_, w| w.enable().bit(false));
+        //reset clocks
+        rcc.cr.modify(|_, w| w.hsion().bit(true));
+        rcc.cfgr.modify(|r, w| w.bits(r.bits() & 0xf8ff0000));
+        rcc.cr.modify(|r, w| w.bits(r.bits() & 0xfef6ffff));
+        rcc.cr.modify(|r, w| w.bits(r.bits() & 0xfffbffff));
+        rcc.cfgr.modify(|r, w| w.bits(r.bits() & 0xff80ffff));
+        rcc.cir.write(|w| w.bits(0));
 
-                    // after this jump it should not return
-                    scb.vtor.write(offset);
-                    cortex_m::register::msp::write(user_msp);
-                    // asm!("bx $0" :: "r" (user_jmp) ::);
-                    asm!("bx {}", in(reg) user_jmp);
-                });
-            }
-        },
-        None => {},
-    }
+        // after this jump it should not return
+        scb.vtor.write(offset);
+        // stack pointer and reset vector of the user code's vector table
+        cortex_m::asm::bootload(flash::PAGE_START as *const u32);
+    });
+}
+
+// Disconnects from the host and resets into the user code
+pub(crate) unsafe fn leave_bootloader() -> ! {
+    let rcc = &*RCC::ptr();
+    let gpioa = &*GPIOA::ptr();
+
+    _log_str("Leaving bootloader\r\n");
+    // let the pending control transfer finish, 10 ms at 48 MHz
+    delay(480_000);
+    cortex_m::interrupt::disable();
+
+    // stop the USB peripheral and pull D+ low so the host sees a disconnect
+    rcc.apb1enr.modify(|_, w| w.usben().clear_bit());
+    gpioa.brr.write(|w| w.br12().set_bit());
+    gpioa.crh.modify(|r, w| w.bits((r.bits() & !(0xf << 16)) | (0x2 << 16)));
+    delay(480_000);
+
+    boot::set_boot_request(boot::BootRequest::Application);
+    SCB::sys_reset();
 }